@@ -1,9 +1,16 @@
 use anyhow::{anyhow, Result};
-use bytes::Buf;
-use futures_util::StreamExt;
-use std::{cmp::min, io::Cursor};
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures_util::{FutureExt, StreamExt};
+use std::{
+    cmp::min,
+    io::{Cursor, IoSlice},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     select,
 };
@@ -13,67 +20,89 @@ use tokio_util::codec::{Decoder, Framed};
 
 pub type TLSStream = Framed<TcpStream, TLSCodec>;
 
+// largest possible TLS record (0x4000) plus room for AEAD expansion
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 0x4000 + 256;
+
 pub struct TLSCodec {
-    buf: Vec<u8>,
-    cursor: usize,
     pub enable_codec: bool,
+    max_record_size: usize,
+    aead: Option<ChaCha20Poly1305>,
+    send_nonce: u64,
+    recv_nonce: u64,
 }
 
 impl TLSCodec {
     pub fn new() -> Self {
         Self {
-            buf: Vec::with_capacity(0x2000),
             enable_codec: true,
-            cursor: 0,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            aead: None,
+            send_nonce: 0,
+            recv_nonce: 0,
         }
     }
 
-    pub fn reset(&mut self) {
-        assert!(self.cursor == self.buf.len());
-        unsafe {
-            self.buf.set_len(0);
-            self.cursor = 0;
+    pub fn with_max_record_size(max_record_size: usize) -> Self {
+        Self {
+            max_record_size,
+            ..Self::new()
         }
     }
 
-    fn peek_record_length(&self) -> usize {
-        5 + ((self.buf[self.cursor + 3] as usize) << 8 | self.buf[self.cursor + 4] as usize)
+    /// Switches this side of the relay from plain `xor_bytes` obfuscation to
+    /// authenticated ChaCha20-Poly1305 framing of the relayed payload.
+    pub fn enable_aead(&mut self, key: &[u8; 32]) {
+        self.aead = Some(ChaCha20Poly1305::new(Key::from_slice(key)));
+        self.send_nonce = 0;
+        self.recv_nonce = 0;
     }
 
-    pub fn next_record(&mut self) -> &mut [u8] {
-        let start = self.cursor;
-        self.cursor += self.peek_record_length();
-        &mut self.buf[start..self.cursor]
+    pub fn aead_enabled(&self) -> bool {
+        self.aead.is_some()
     }
 
-    pub fn peek_record(&self) -> &[u8] {
-        let len = self.peek_record_length();
-        &self.buf[self.cursor..self.cursor + len]
-    }
-    pub fn peek_record_type(&self) -> u8 {
-        self.buf[self.cursor]
+    fn next_nonce(counter: &mut u64) -> Nonce {
+        let n = *counter;
+        *counter = counter
+            .checked_add(1)
+            .expect("AEAD record nonce counter wrapped");
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes[..8].copy_from_slice(&n.to_le_bytes());
+        *Nonce::from_slice(&nonce_bytes)
     }
 
-    pub fn has_next(&self) -> bool {
-        self.cursor < self.buf.len()
+    /// Seals `payload` in place with the send-direction key, appending the
+    /// 16-byte authentication tag.
+    pub fn seal(&mut self, payload: &mut Vec<u8>) -> Result<()> {
+        let aead = self
+            .aead
+            .as_ref()
+            .ok_or_else(|| anyhow!("AEAD framing is not enabled"))?;
+        let nonce = Self::next_nonce(&mut self.send_nonce);
+        *payload = aead
+            .encrypt(&nonce, payload.as_slice())
+            .map_err(|_| anyhow!("failed to seal record"))?;
+        Ok(())
     }
 
-    pub fn skip_to_end(&mut self) {
-        self.cursor = self.buf.len();
-    }
-
-    pub fn raw_buf(&self) -> &[u8] {
-        assert!(self.cursor == self.buf.len());
-        &self.buf
-    }
-
-    pub fn has_content(&self) -> bool {
-        !self.buf.is_empty()
+    /// Verifies and opens a record payload (ciphertext plus trailing tag)
+    /// sealed with the peer's matching `seal` call.
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let aead = self
+            .aead
+            .as_ref()
+            .ok_or_else(|| anyhow!("AEAD framing is not enabled"))?;
+        let nonce = Self::next_nonce(&mut self.recv_nonce);
+        aead.decrypt(&nonce, ciphertext)
+            .map_err(|_| anyhow!("record failed authentication"))
     }
 }
 
 impl Decoder for TLSCodec {
-    type Item = ();
+    /// One fully-buffered TLS record (5-byte header plus body), split out
+    /// of `src` without copying. With `enable_codec` off, all bytes buffered
+    /// in `src` instead, unparsed.
+    type Item = BytesMut;
 
     type Error = anyhow::Error;
 
@@ -81,43 +110,33 @@ impl Decoder for TLSCodec {
         &mut self,
         src: &mut bytes::BytesMut,
     ) -> std::result::Result<Option<Self::Item>, Self::Error> {
-        self.reset();
-
         if !self.enable_codec {
-            if src.len() == 0 {
+            if src.is_empty() {
                 return Ok(None);
             }
-            self.buf.extend_from_slice(&src);
-            src.advance(src.len());
-            return Ok(Some(()));
+            let len = src.len();
+            return Ok(Some(src.split_to(len)));
         }
 
         if src.len() < 5 {
             debug!("src len < 5");
             return Ok(None);
         }
-        let mut cursor = 0;
-        while cursor + 5 < src.len() {
-            let record_len = ((src[cursor + 3] as u16) << 8 | (src[cursor + 4] as u16)) as usize;
-            debug!("incoming record len: {}", record_len);
-            if src.len() < cursor + 5 + record_len {
-                break;
-            }
-            cursor += 5 + record_len;
+        let record_len = ((src[3] as u16) << 8 | (src[4] as u16)) as usize;
+        if record_len > self.max_record_size {
+            return Err(anyhow!(
+                "record len {} exceeds max_record_size {}",
+                record_len,
+                self.max_record_size
+            ));
         }
-        if cursor == 0 {
+        let total_len = 5 + record_len;
+        if src.len() < total_len {
+            src.reserve(total_len - src.len());
             return Ok(None);
         }
-        self.buf.reserve(cursor);
-        unsafe {
-            self.buf.set_len(cursor);
-        }
-
-        src.copy_to_slice(&mut self.buf);
 
-        tracing::debug!("decoded: {}", self.buf.len());
-
-        Ok(Some(()))
+        Ok(Some(src.split_to(total_len)))
     }
 }
 
@@ -177,77 +196,151 @@ pub(crate) fn xor_bytes(secret: &[u8], msg: &mut [u8]) {
     }
 }
 
+/// A record's relayed payload, borrowed straight out of the decoded
+/// `BytesMut` when no transform is needed, or owned when AEAD opening
+/// produced a fresh plaintext buffer.
+enum RecordPayload<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+}
+
+impl RecordPayload<'_> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            RecordPayload::Borrowed(s) => s,
+            RecordPayload::Owned(v) => v,
+        }
+    }
+}
+
+/// Drains every record that is already fully buffered on `inbound` without
+/// waiting for more bytes to arrive, so a batch decoded from a single TCP
+/// read can be flushed with one vectored write instead of one syscall per
+/// record.
+async fn drain_ready_records(inbound: &mut TLSStream, first: BytesMut) -> Vec<BytesMut> {
+    let mut records = vec![first];
+    while let Some(Some(Ok(record))) = inbound.next().now_or_never() {
+        records.push(record);
+    }
+    records
+}
+
+/// Forwards a batch of already-decoded records to `outbound`, opening the
+/// AEAD tag first when AEAD framing is enabled, and writes them all with a
+/// single vectored write when the stream supports it. `content_offset` is
+/// applied to the first record only (subsequent records are sliced past
+/// their 5-byte header).
+async fn relay_records(
+    inbound: &mut TLSStream,
+    outbound: &mut TcpStream,
+    records: &[BytesMut],
+    content_offset: usize,
+) -> Result<()> {
+    let mut payloads = Vec::with_capacity(records.len());
+    let mut offset = content_offset;
+    for record in records {
+        if inbound.codec().aead_enabled() {
+            payloads.push(RecordPayload::Owned(
+                inbound.codec_mut().open(&record[offset..])?,
+            ));
+        } else {
+            payloads.push(RecordPayload::Borrowed(&record[offset..]));
+        }
+        offset = 5;
+    }
+
+    if !outbound.is_write_vectored() {
+        for payload in &payloads {
+            outbound.write_all(payload.as_slice()).await?;
+        }
+        return Ok(());
+    }
+
+    let mut slices: Vec<IoSlice<'_>> = payloads.iter().map(|p| IoSlice::new(p.as_slice())).collect();
+    let mut slices = slices.as_mut_slice();
+    while !slices.is_empty() {
+        let n = outbound.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(anyhow!("failed to write to outbound: "));
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    Ok(())
+}
+
+/// Relays TLS records between `inbound` and `outbound`; records that are
+/// already buffered together are forwarded as one batch. `pending_record`, if given, is a record the caller already decoded (e.g.
+/// while peeking at the handshake) and still needs forwarding before the
+/// steady-state relay loop starts.
 pub async fn copy_bidirectional(
     mut inbound: TLSStream,
     mut outbound: TcpStream,
-    mut content_offset: usize,
+    pending_record: Option<BytesMut>,
+    content_offset: usize,
 ) -> Result<()> {
     let mut out_buf = [0; 0x2000];
     out_buf[..3].copy_from_slice(&[0x17, 0x03, 0x03]);
-    while inbound.codec().has_next() {
-        outbound
-            .write_all(&inbound.codec_mut().next_record()[content_offset..])
-            .await?;
-        content_offset = 5;
-    }
 
-    inbound.codec_mut().reset();
+    if let Some(record) = pending_record {
+        let records = drain_ready_records(&mut inbound, record).await;
+        relay_records(&mut inbound, &mut outbound, &records, content_offset).await?;
+    }
 
     loop {
         select! {
             res = inbound.next() => {
-                match res {
-                    Some(Ok(_)) => (),
+                let record = match res {
+                    Some(Ok(record)) => record,
                     e => {
                         e.ok_or(anyhow!("failed to read from inbound: "))??;
+                        unreachable!()
                     }
-                }
-                while inbound.codec().has_next() {
-                    outbound
-                        .write_all(&inbound.codec_mut().next_record()[5..])
-                        .await?;
-                }
-                inbound.codec_mut().reset();
+                };
+                let records = drain_ready_records(&mut inbound, record).await;
+                relay_records(&mut inbound, &mut outbound, &records, 5).await?;
             }
             n = outbound.read(&mut out_buf[5..]) => {
                 let n = n?;
                 if n == 0 {
                     return Err(anyhow!("failed to read from outbound: "));
                 }
-                out_buf[3..5].copy_from_slice(&(n as u16).to_be_bytes());
-                inbound.get_mut().write_all(&out_buf[..n+5]).await?;
+                if inbound.codec().aead_enabled() {
+                    let mut payload = out_buf[5..5 + n].to_vec();
+                    inbound.codec_mut().seal(&mut payload)?;
+                    let mut header = [0x17, 0x03, 0x03, 0, 0];
+                    header[3..5].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+                    let stream = inbound.get_mut();
+                    stream.write_all(&header).await?;
+                    stream.write_all(&payload).await?;
+                } else {
+                    out_buf[3..5].copy_from_slice(&(n as u16).to_be_bytes());
+                    inbound.get_mut().write_all(&out_buf[..n + 5]).await?;
+                }
             }
         }
     }
 }
 
+/// Relays raw bytes between `inbound` and `outbound` with TLS parsing
+/// switched off. `inbound_pending`/`outbound_pending` carry any record the
+/// caller already decoded from that side before falling back and still
+/// needs forwarding.
 pub async fn copy_bidirectional_fallback(
     mut inbound: TLSStream,
     mut outbound: TLSStream,
+    inbound_pending: Option<BytesMut>,
+    outbound_pending: Option<BytesMut>,
 ) -> Result<()> {
     inbound.codec_mut().enable_codec = false;
     outbound.codec_mut().enable_codec = false;
-    if inbound.codec().has_content() {
-        inbound.codec_mut().skip_to_end();
-        debug!(
-            "write old msg to inbound {}",
-            inbound.codec().raw_buf().len()
-        );
-        outbound
-            .get_mut()
-            .write_all(inbound.codec().raw_buf())
-            .await?;
-    }
-    if outbound.codec().has_content() {
-        outbound.codec_mut().skip_to_end();
-        debug!(
-            "write old msg to outbound {}",
-            outbound.codec().raw_buf().len()
-        );
-        inbound
-            .get_mut()
-            .write_all(outbound.codec().raw_buf())
-            .await?;
+
+    if let Some(record) = inbound_pending {
+        debug!("write old msg to inbound {}", record.len());
+        outbound.get_mut().write_all(&record).await?;
+    }
+    if let Some(record) = outbound_pending {
+        debug!("write old msg to outbound {}", record.len());
+        inbound.get_mut().write_all(&record).await?;
     }
 
     debug!("start relaying");
@@ -255,25 +348,588 @@ pub async fn copy_bidirectional_fallback(
     loop {
         select! {
             res = inbound.next() => {
-                match res {
-                    Some(Ok(_)) => (),
+                let record = match res {
+                    Some(Ok(record)) => record,
                     e => {
                         e.ok_or(anyhow!("failed to read from inbound: "))??;
+                        unreachable!()
                     }
-                }
-                inbound.codec_mut().skip_to_end();
-                outbound.get_mut().write_all(inbound.codec().raw_buf()).await?;
+                };
+                outbound.get_mut().write_all(&record).await?;
             }
             res = outbound.next() => {
-                match res {
-                    Some(Ok(_))  => (),
+                let record = match res {
+                    Some(Ok(record)) => record,
                     e => {
                         e.ok_or(anyhow!("failed to read from outbound: "))??;
+                        unreachable!()
                     }
+                };
+                inbound.get_mut().write_all(&record).await?;
+            }
+        }
+    }
+}
+
+pub type WsStream = Framed<TcpStream, WsCodec>;
+
+const WS_OP_CONTINUATION: u8 = 0x0;
+const WS_OP_TEXT: u8 = 0x1;
+const WS_OP_BINARY: u8 = 0x2;
+const WS_OP_CLOSE: u8 = 0x8;
+const WS_OP_PING: u8 = 0x9;
+const WS_OP_PONG: u8 = 0xa;
+
+/// Speaks RFC 6455 framing instead of TLS records, so a relay can be
+/// tunnelled through an HTTP/WebSocket-terminating CDN. Decoded binary/text
+/// frame payloads from one `decode` call accumulate in `buf`: callers drain
+/// them with `raw_buf`/`reset`.
+pub struct WsCodec {
+    buf: Vec<u8>,
+    max_frame_size: usize,
+    pending_pong: Option<Vec<u8>>,
+    pending_close: Option<Vec<u8>>,
+    // Opcode and accumulated payload of a data message currently being
+    // reassembled from continuation frames, if any.
+    fragment_opcode: Option<u8>,
+    fragment_buf: Vec<u8>,
+}
+
+impl WsCodec {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::with_capacity(0x2000),
+            max_frame_size: DEFAULT_MAX_RECORD_SIZE,
+            pending_pong: None,
+            pending_close: None,
+            fragment_opcode: None,
+            fragment_buf: Vec::new(),
+        }
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            ..Self::new()
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn has_content(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    pub fn raw_buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Returns the application data of the most recent unsolicited Ping,
+    /// if any, so the caller can reply with a Pong carrying the same data.
+    pub fn take_pending_pong(&mut self) -> Option<Vec<u8>> {
+        self.pending_pong.take()
+    }
+
+    /// Returns the peer's Close reason, if the peer just closed the
+    /// connection, so the caller can echo it back before tearing down.
+    pub fn take_pending_close(&mut self) -> Option<Vec<u8>> {
+        self.pending_close.take()
+    }
+}
+
+/// Builds an unmasked RFC 6455 frame header (server-to-client frames MUST
+/// NOT be masked) for `payload_len` bytes of the given opcode.
+pub fn ws_frame_header(opcode: u8, payload_len: usize) -> Vec<u8> {
+    let mut header = vec![0x80 | opcode];
+    if payload_len < 126 {
+        header.push(payload_len as u8);
+    } else if payload_len <= u16::MAX as usize {
+        header.push(126);
+        header.extend_from_slice(&(payload_len as u16).to_be_bytes());
+    } else {
+        header.push(127);
+        header.extend_from_slice(&(payload_len as u64).to_be_bytes());
+    }
+    header
+}
+
+impl Decoder for WsCodec {
+    type Item = ();
+
+    type Error = anyhow::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut bytes::BytesMut,
+    ) -> std::result::Result<Option<Self::Item>, Self::Error> {
+        self.reset();
+
+        let mut yielded = false;
+        loop {
+            if src.len() < 2 {
+                break;
+            }
+            let fin = src[0] & 0x80 != 0;
+            let opcode = src[0] & 0x0f;
+            let masked = src[1] & 0x80 != 0;
+            let mut payload_len = (src[1] & 0x7f) as usize;
+            let mut header_len = 2;
+            if payload_len == 126 {
+                if src.len() < header_len + 2 {
+                    break;
+                }
+                payload_len = u16::from_be_bytes([src[2], src[3]]) as usize;
+                header_len += 2;
+            } else if payload_len == 127 {
+                if src.len() < header_len + 8 {
+                    break;
                 }
-                outbound.codec_mut().skip_to_end();
-                inbound.get_mut().write_all(outbound.codec().raw_buf()).await?;
+                payload_len =
+                    u64::from_be_bytes(src[2..10].try_into().unwrap()) as usize;
+                header_len += 8;
+            }
+            if !masked {
+                return Err(anyhow!("client ws frame is not masked"));
+            }
+            if payload_len > self.max_frame_size {
+                return Err(anyhow!(
+                    "ws frame len {} exceeds max_frame_size {}",
+                    payload_len,
+                    self.max_frame_size
+                ));
+            }
+            let mask_offset = header_len;
+            header_len += 4;
+            if src.len() < header_len + payload_len {
+                break;
+            }
+            let mask_key = [
+                src[mask_offset],
+                src[mask_offset + 1],
+                src[mask_offset + 2],
+                src[mask_offset + 3],
+            ];
+            let mut payload = src[header_len..header_len + payload_len].to_vec();
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask_key[i % 4];
             }
+            src.advance(header_len + payload_len);
+
+            if matches!(opcode, WS_OP_PING | WS_OP_PONG | WS_OP_CLOSE) && !fin {
+                return Err(anyhow!("control ws frames must not be fragmented"));
+            }
+
+            match opcode {
+                WS_OP_TEXT | WS_OP_BINARY => {
+                    if self.fragment_opcode.is_some() {
+                        return Err(anyhow!(
+                            "received a new data frame while a fragmented message is in progress"
+                        ));
+                    }
+                    if fin {
+                        self.buf.extend_from_slice(&payload);
+                        yielded = true;
+                    } else {
+                        self.fragment_opcode = Some(opcode);
+                        self.fragment_buf = payload;
+                    }
+                }
+                WS_OP_CONTINUATION => {
+                    if self.fragment_opcode.is_none() {
+                        return Err(anyhow!(
+                            "continuation frame without a preceding fragmented message"
+                        ));
+                    }
+                    if self.fragment_buf.len() + payload.len() > self.max_frame_size {
+                        return Err(anyhow!(
+                            "reassembled ws message exceeds max_frame_size {}",
+                            self.max_frame_size
+                        ));
+                    }
+                    self.fragment_buf.extend_from_slice(&payload);
+                    if fin {
+                        self.fragment_opcode = None;
+                        self.buf.append(&mut self.fragment_buf);
+                        yielded = true;
+                    }
+                }
+                WS_OP_PING => {
+                    self.pending_pong = Some(payload);
+                    yielded = true;
+                }
+                WS_OP_PONG => (),
+                WS_OP_CLOSE => {
+                    self.pending_close = Some(payload);
+                    yielded = true;
+                    break;
+                }
+                _ => return Err(anyhow!("unsupported ws opcode {}", opcode)),
+            }
+        }
+
+        if yielded {
+            Ok(Some(()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Same relay as `copy_bidirectional`, but over a WebSocket-framed
+/// `inbound` instead of TLS records — lets a relay tunnel through a CDN
+/// that only forwards HTTP/WebSocket traffic.
+pub async fn copy_bidirectional_ws(mut inbound: WsStream, mut outbound: TcpStream) -> Result<()> {
+    if inbound.codec().has_content() {
+        outbound.write_all(inbound.codec().raw_buf()).await?;
+    }
+    inbound.codec_mut().reset();
+
+    let mut out_buf = [0; 0x2000];
+    loop {
+        select! {
+            res = inbound.next() => {
+                match res {
+                    Some(Ok(_)) => (),
+                    e => {
+                        e.ok_or(anyhow!("failed to read from inbound: "))??;
+                    }
+                }
+                if inbound.codec().has_content() {
+                    outbound.write_all(inbound.codec().raw_buf()).await?;
+                }
+                inbound.codec_mut().reset();
+                if let Some(payload) = inbound.codec_mut().take_pending_pong() {
+                    let header = ws_frame_header(WS_OP_PONG, payload.len());
+                    let stream = inbound.get_mut();
+                    stream.write_all(&header).await?;
+                    stream.write_all(&payload).await?;
+                }
+                if let Some(reason) = inbound.codec_mut().take_pending_close() {
+                    let header = ws_frame_header(WS_OP_CLOSE, reason.len());
+                    let stream = inbound.get_mut();
+                    stream.write_all(&header).await?;
+                    stream.write_all(&reason).await?;
+                    return Ok(());
+                }
+            }
+            n = outbound.read(&mut out_buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Err(anyhow!("failed to read from outbound: "));
+                }
+                let header = ws_frame_header(WS_OP_BINARY, n);
+                let stream = inbound.get_mut();
+                stream.write_all(&header).await?;
+                stream.write_all(&out_buf[..n]).await?;
+            }
+        }
+    }
+}
+
+/// Selects which framing a relay speaks to the client, so the caller can
+/// pick TLS-record framing or WebSocket framing (for CDN traversal) at
+/// connection time.
+pub enum Transport {
+    Tls(TLSStream),
+    Ws(WsStream),
+}
+
+impl Transport {
+    pub async fn relay(
+        self,
+        outbound: TcpStream,
+        pending_record: Option<BytesMut>,
+        content_offset: usize,
+    ) -> Result<()> {
+        match self {
+            Transport::Tls(inbound) => {
+                copy_bidirectional(inbound, outbound, pending_record, content_offset).await
+            }
+            Transport::Ws(inbound) => copy_bidirectional_ws(inbound, outbound).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod aead_tests {
+    use super::*;
+
+    #[test]
+    fn seal_open_round_trip() {
+        let key = [7u8; 32];
+        let mut sender = TLSCodec::new();
+        sender.enable_aead(&key);
+        let mut receiver = TLSCodec::new();
+        receiver.enable_aead(&key);
+
+        let mut payload = b"hello restls".to_vec();
+        sender.seal(&mut payload).unwrap();
+        assert_eq!(payload.len(), b"hello restls".len() + 16);
+
+        let plaintext = receiver.open(&payload).unwrap();
+        assert_eq!(plaintext, b"hello restls");
+    }
+
+    #[test]
+    fn nonce_counter_rejects_out_of_order_records() {
+        let key = [3u8; 32];
+        let mut sender = TLSCodec::new();
+        sender.enable_aead(&key);
+        let mut receiver = TLSCodec::new();
+        receiver.enable_aead(&key);
+
+        let mut first = b"first".to_vec();
+        sender.seal(&mut first).unwrap();
+        let mut second = b"second".to_vec();
+        sender.seal(&mut second).unwrap();
+
+        // The receiver's nonce counter expects `first` next; feeding it
+        // `second` must fail authentication instead of silently opening.
+        assert!(receiver.open(&second).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_authentication() {
+        let key = [9u8; 32];
+        let mut sender = TLSCodec::new();
+        sender.enable_aead(&key);
+        let mut receiver = TLSCodec::new();
+        receiver.enable_aead(&key);
+
+        let mut payload = b"restls".to_vec();
+        sender.seal(&mut payload).unwrap();
+        *payload.last_mut().unwrap() ^= 0xff;
+
+        assert!(receiver.open(&payload).is_err());
+    }
+
+    #[test]
+    fn seal_and_open_require_aead_to_be_enabled() {
+        let mut codec = TLSCodec::new();
+        assert!(codec.seal(&mut b"plain".to_vec()).is_err());
+        assert!(codec.open(b"plain").is_err());
+    }
+}
+
+#[cfg(test)]
+mod ws_codec_tests {
+    use super::*;
+
+    pub(crate) fn masked_frame(opcode: u8, fin: bool, mask_key: [u8; 4], payload: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(&[(if fin { 0x80 } else { 0 }) | opcode]);
+        let len = payload.len();
+        if len < 126 {
+            frame.extend_from_slice(&[0x80 | len as u8]);
+        } else if len <= u16::MAX as usize {
+            frame.extend_from_slice(&[0x80 | 126]);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.extend_from_slice(&[0x80 | 127]);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
         }
+        frame.extend_from_slice(&mask_key);
+        let masked: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask_key[i % 4])
+            .collect();
+        frame.extend_from_slice(&masked);
+        frame
+    }
+
+    #[test]
+    fn decodes_small_masked_binary_frame() {
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_BINARY, true, [1, 2, 3, 4], b"hi");
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert_eq!(codec.raw_buf(), b"hi");
+    }
+
+    #[test]
+    fn decodes_16_bit_extended_length() {
+        let payload = vec![0x42u8; 200];
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_BINARY, true, [9, 9, 9, 9], &payload);
+        codec.decode(&mut src).unwrap();
+        assert_eq!(codec.raw_buf(), payload.as_slice());
+    }
+
+    #[test]
+    fn decodes_64_bit_extended_length() {
+        let payload = vec![0x11u8; 70_000];
+        let mut codec = WsCodec::with_max_frame_size(1 << 20);
+        let mut src = masked_frame(WS_OP_BINARY, true, [5, 6, 7, 8], &payload);
+        codec.decode(&mut src).unwrap();
+        assert_eq!(codec.raw_buf(), payload.as_slice());
+    }
+
+    #[test]
+    fn unmasked_client_frame_is_rejected() {
+        let mut codec = WsCodec::new();
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(&[0x82, 0x02]);
+        frame.extend_from_slice(b"hi");
+        assert!(codec.decode(&mut frame).is_err());
+    }
+
+    #[test]
+    fn ping_is_surfaced_as_pending_pong() {
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_PING, true, [1, 1, 1, 1], b"ping-data");
+        codec.decode(&mut src).unwrap();
+        assert_eq!(codec.take_pending_pong().unwrap(), b"ping-data");
+    }
+
+    #[test]
+    fn close_is_surfaced_as_pending_close() {
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_CLOSE, true, [2, 2, 2, 2], b"bye");
+        codec.decode(&mut src).unwrap();
+        assert_eq!(codec.take_pending_close().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn reassembles_fragmented_message() {
+        let mut codec = WsCodec::new();
+        let mut first = masked_frame(WS_OP_BINARY, false, [1, 2, 3, 4], b"hello ");
+        codec.decode(&mut first).unwrap();
+        assert!(!codec.has_content());
+
+        let mut second = masked_frame(WS_OP_CONTINUATION, true, [4, 3, 2, 1], b"world");
+        codec.decode(&mut second).unwrap();
+        assert_eq!(codec.raw_buf(), b"hello world");
+    }
+
+    #[test]
+    fn continuation_without_a_fragment_in_progress_is_rejected() {
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_CONTINUATION, true, [1, 2, 3, 4], b"orphan");
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_rejected() {
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_PING, false, [1, 2, 3, 4], b"partial-ping");
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut codec = WsCodec::with_max_frame_size(4);
+        let mut src = masked_frame(WS_OP_BINARY, true, [1, 2, 3, 4], b"too long");
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn decode_retains_both_payload_and_close_batched_in_one_read() {
+        let mut codec = WsCodec::new();
+        let mut src = masked_frame(WS_OP_BINARY, true, [1, 2, 3, 4], b"final-data");
+        src.unsplit(masked_frame(WS_OP_CLOSE, true, [5, 6, 7, 8], b""));
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert_eq!(codec.raw_buf(), b"final-data");
+        assert!(codec.take_pending_close().is_some());
+    }
+}
+
+#[cfg(test)]
+mod tls_codec_tests {
+    use super::*;
+
+    fn record(record_len: usize) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0x17, 0x03, 0x03]);
+        buf.extend_from_slice(&(record_len as u16).to_be_bytes());
+        buf.extend_from_slice(&vec![0x41u8; record_len]);
+        buf
+    }
+
+    #[test]
+    fn partial_header_returns_none() {
+        let mut codec = TLSCodec::new();
+        let mut src = BytesMut::from(&[0x17, 0x03, 0x03, 0x00][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), 4);
+    }
+
+    #[test]
+    fn partial_body_returns_none() {
+        let mut codec = TLSCodec::new();
+        let mut full = record(100);
+        let mut src = full.split_to(full.len() - 1);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn full_record_is_split_without_copying() {
+        let mut codec = TLSCodec::new();
+        let mut src = record(100);
+        let ptr_before = src.as_ptr();
+        let out = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(out.as_ptr(), ptr_before);
+        assert_eq!(out.len(), 105);
+        assert_eq!(&out[5..], &vec![0x41u8; 100][..]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn record_exactly_at_max_record_size_is_accepted() {
+        let mut codec = TLSCodec::with_max_record_size(DEFAULT_MAX_RECORD_SIZE);
+        let mut src = record(DEFAULT_MAX_RECORD_SIZE);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn record_exceeding_max_record_size_is_rejected() {
+        let mut codec = TLSCodec::with_max_record_size(DEFAULT_MAX_RECORD_SIZE);
+        let mut src = record(DEFAULT_MAX_RECORD_SIZE + 1);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn disabled_codec_passes_through_raw_bytes() {
+        let mut codec = TLSCodec::new();
+        codec.enable_codec = false;
+        let mut src = BytesMut::from(&b"arbitrary bytes, no framing"[..]);
+        let out = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(&out[..], b"arbitrary bytes, no framing");
+        assert!(src.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod ws_relay_tests {
+    use super::ws_codec_tests::masked_frame;
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn loopback_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (server, _) = accepted.unwrap();
+        (client.unwrap(), server)
+    }
+
+    #[tokio::test]
+    async fn flushes_final_payload_before_acting_on_a_batched_close() {
+        let (mut ws_client, ws_server) = loopback_pair().await;
+        let (out_client, mut out_server) = loopback_pair().await;
+
+        let inbound = Framed::new(ws_server, WsCodec::new());
+        let relay = tokio::spawn(copy_bidirectional_ws(inbound, out_client));
+
+        let mut packet = masked_frame(WS_OP_BINARY, true, [1, 2, 3, 4], b"final-data");
+        packet.unsplit(masked_frame(WS_OP_CLOSE, true, [5, 6, 7, 8], b""));
+        ws_client.write_all(&packet).await.unwrap();
+
+        let mut received = vec![0u8; b"final-data".len()];
+        out_server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"final-data");
+
+        relay.await.unwrap().unwrap();
     }
 }